@@ -0,0 +1,77 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+/// A named, server-side OpenAI credential profile. Clients reference a profile by name
+/// instead of embedding an `api_key` in the request body, so secrets never leave the server.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Profile {
+    pub api_key: String,
+    pub model: String,
+    pub api_base: Option<String>,
+    pub max_tokens: u16,
+    pub temperature: f32,
+}
+
+pub type Profiles = HashMap<String, Profile>;
+
+#[derive(Debug, Deserialize)]
+struct ProfilesFile {
+    #[serde(flatten)]
+    profiles: Profiles,
+}
+
+const PROFILES_PATH_VAR: &str = "PROFILES_PATH";
+const DEFAULT_PROFILES_PATH: &str = "profiles.toml";
+
+/// Loads the named profiles this server will answer for. Reads the TOML file pointed to
+/// by `PROFILES_PATH` (defaulting to `profiles.toml`); if that file doesn't exist, falls
+/// back to a single `default` profile built from `OPENAI_API_KEY` and friends so a bare
+/// `docker run -e OPENAI_API_KEY=...` still works.
+pub fn load_profiles() -> Result<Profiles, Box<dyn Error>> {
+    let path = std::env::var(PROFILES_PATH_VAR).unwrap_or_else(|_| DEFAULT_PROFILES_PATH.to_owned());
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            let file: ProfilesFile = toml::from_str(&contents)?;
+            Ok(file.profiles)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => profiles_from_env(),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn profiles_from_env() -> Result<Profiles, Box<dyn Error>> {
+    let api_key = std::env::var("OPENAI_API_KEY")
+        .map_err(|_| format!("no {PROFILES_PATH_VAR} file and no OPENAI_API_KEY set"))?;
+
+    let profile = Profile {
+        api_key,
+        model: std::env::var("OPENAI_MODEL").unwrap_or_else(|_| "gpt-3.5-turbo".to_owned()),
+        api_base: std::env::var("OPENAI_API_BASE").ok(),
+        max_tokens: std::env::var("OPENAI_MAX_TOKENS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(512),
+        temperature: std::env::var("OPENAI_TEMPERATURE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0.7),
+    };
+
+    let mut profiles = Profiles::new();
+    profiles.insert("default".to_owned(), profile);
+    Ok(profiles)
+}
+
+const MAX_BATCH_SIZE_VAR: &str = "MAX_BATCH_SIZE";
+const DEFAULT_MAX_BATCH_SIZE: usize = 4;
+
+/// How many `/answer/batch` prompts are sent to the upstream provider concurrently,
+/// read from `MAX_BATCH_SIZE` (defaulting to 4) so operators can tune it per deployment.
+pub fn max_batch_size() -> usize {
+    std::env::var(MAX_BATCH_SIZE_VAR)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BATCH_SIZE)
+}
@@ -1,15 +1,31 @@
 use warp::{Filter, Rejection, Reply};
 use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
 use std::error::Error;
+use std::time::Duration;
 use async_openai::{
     types::{CreateChatCompletionRequestArgs, Role},
     Client,
 };
 use async_openai::config::OpenAIConfig;
-use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionResponse};
-use tokio::time::timeout;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionFunctionCall, ChatCompletionFunctions, ChatCompletionRequestMessage,
+    CreateChatCompletionResponse, CreateChatCompletionStreamResponse, FunctionCall,
+};
+use futures::stream::{self, BoxStream, StreamExt};
+use rand::Rng;
+use std::sync::Arc;
+use tokio::time::{timeout, Instant};
+use url::Url;
+
+mod config;
+use config::{Profile, Profiles};
 
 const DEFAULT_TIMEOUT: u64 = 120;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const MAX_RETRIES_CAP: u32 = 10;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
 
 #[derive(Debug, Deserialize, Serialize)]
 struct PingResponse {
@@ -20,16 +36,29 @@ struct PingResponse {
 struct ChatMessage {
     role: Role,
     content: String,
+    name: Option<String>,
+    function_call: Option<FunctionCall>,
+}
+
+/// A function the model may choose to call, mirroring OpenAI's function-calling schema:
+/// a name, an optional human-readable description, and a JSON-schema `parameters` object.
+#[derive(Debug, Deserialize, Serialize)]
+struct FunctionDefinition {
+    name: String,
+    description: Option<String>,
+    parameters: serde_json::Value,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct OpenAiRequest {
-    api_key: String,
-    model: String,
-    max_tokens: u16,
-    temperature: f32,
+    profile: String,
+    model: Option<String>,
+    temperature: Option<f32>,
     timeout: Option<u64>,
     messages: Vec<ChatMessage>,
+    functions: Option<Vec<FunctionDefinition>>,
+    function_call: Option<ChatCompletionFunctionCall>,
+    max_retries: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -39,14 +68,88 @@ struct ApiResponse {
     error: Option<String>,
 }
 
-async fn get_response(request: OpenAiRequest) -> Result<CreateChatCompletionResponse, Box<dyn Error>> {
+/// Looks up a named profile, rejecting requests for one that wasn't configured server-side.
+fn resolve_profile<'a>(profiles: &'a Profiles, name: &str) -> Result<&'a Profile, String> {
+    profiles
+        .get(name)
+        .ok_or_else(|| format!("unknown profile: {name}"))
+}
+
+/// Builds the `async-openai` config for a profile, routing to a custom
+/// OpenAI-compatible gateway (Perplexity, Mistral, a local Ollama shim, ...)
+/// when the profile's `api_base` is set. Rejects anything that doesn't parse as a URL.
+fn build_config(profile: &Profile) -> Result<OpenAIConfig, String> {
+    let config = OpenAIConfig::new().with_api_key(profile.api_key.clone());
+
+    match &profile.api_base {
+        Some(base) => {
+            Url::parse(base).map_err(|e| format!("invalid api_base: {e}"))?;
+            Ok(config.with_api_base(base.clone()))
+        }
+        None => Ok(config),
+    }
+}
+
+fn converted_messages(messages: &[ChatMessage]) -> Vec<ChatCompletionRequestMessage> {
+    messages
+        .iter()
+        .map(|m| ChatCompletionRequestMessage {
+            role: m.role.clone(),
+            content: Some(m.content.clone()),
+            name: m.name.clone(),
+            function_call: m.function_call.clone(),
+        })
+        .collect()
+}
+
+fn converted_functions(functions: &[FunctionDefinition]) -> Vec<ChatCompletionFunctions> {
+    functions
+        .iter()
+        .map(|f| ChatCompletionFunctions {
+            name: f.name.clone(),
+            description: f.description.clone(),
+            parameters: f.parameters.clone(),
+        })
+        .collect()
+}
+
+/// Builds the outgoing chat completion request: `model`/`temperature` come from the
+/// profile unless the request overrides them, `max_tokens` always comes from the profile,
+/// and the optional function definitions/function-call control ride along unchanged.
+fn build_chat_request(
+    request: &OpenAiRequest,
+    profile: &Profile,
+    messages: Vec<ChatCompletionRequestMessage>,
+) -> Result<async_openai::types::CreateChatCompletionRequest, OpenAIError> {
+    let model = request.model.clone().unwrap_or_else(|| profile.model.clone());
+    let temperature = request.temperature.unwrap_or(profile.temperature);
+
+    let mut builder = CreateChatCompletionRequestArgs::default();
+    builder
+        .max_tokens(profile.max_tokens)
+        .model(model)
+        .temperature(temperature)
+        .messages(messages);
+
+    if let Some(functions) = &request.functions {
+        builder.functions(converted_functions(functions));
+    }
+    if let Some(function_call) = &request.function_call {
+        builder.function_call(function_call.clone());
+    }
+
+    builder.build()
+}
+
+async fn get_response(request: OpenAiRequest, profiles: &Profiles) -> Result<CreateChatCompletionResponse, Box<dyn Error>> {
     let req_timeout = match request.timeout {
         Some(x) => x,
         _ => DEFAULT_TIMEOUT
     };
     let duration = tokio::time::Duration::from_secs(req_timeout);
 
-    let config = OpenAIConfig::new().with_api_key(request.api_key);
+    let profile = resolve_profile(profiles, &request.profile)?;
+    let config = build_config(profile)?;
 
     let client = Client::with_config(config);
 
@@ -54,26 +157,13 @@ async fn get_response(request: OpenAiRequest) -> Result<CreateChatCompletionResp
         return Err("No response from GPT-3.5 Turbo".into())
     }
 
-    let converted_messages: Vec<ChatCompletionRequestMessage> = request.messages
-    .iter()
-    .map(|m| ChatCompletionRequestMessage {
-        role: m.role.clone(),
-        content: Some(m.content.clone()),
-        name: None,
-        function_call: None,
-    }) // Build each message inside the map
-    .collect::<Vec<ChatCompletionRequestMessage>>();
-
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(request.max_tokens)
-        .model(request.model)
-        .temperature(request.temperature)
-        .messages(converted_messages)
-        .build()?;
-
-    let task = async {
-        client.chat().create(request).await
-    };
+    let converted_messages = converted_messages(&request.messages);
+    let chat_request = build_chat_request(&request, profile, converted_messages)?;
+    // Clamped server-side: a client-supplied `max_retries` must not be able to drive the
+    // exponential backoff below into an overflow.
+    let max_retries = request.max_retries.unwrap_or(DEFAULT_MAX_RETRIES).min(MAX_RETRIES_CAP);
+
+    let task = create_with_retry(&client, chat_request, max_retries);
     let result = timeout(duration, task).await?;
 
     return match result {
@@ -84,6 +174,88 @@ async fn get_response(request: OpenAiRequest) -> Result<CreateChatCompletionResp
     }
 }
 
+/// True for upstream errors worth retrying: HTTP 429 (rate limit) and 5xx responses.
+fn is_transient(err: &OpenAIError) -> bool {
+    match err {
+        OpenAIError::Reqwest(e) => e
+            .status()
+            .map(|status| status.as_u16() == 429 || status.is_server_error())
+            .unwrap_or(false),
+        OpenAIError::ApiError(e) => {
+            let haystack = format!("{} {}", e.code.clone().unwrap_or_default(), e.message).to_lowercase();
+            haystack.contains("rate_limit") || haystack.contains("rate limit") || haystack.contains("server_error")
+        }
+        _ => false,
+    }
+}
+
+/// Calls `client.chat().create(...)`, retrying up to `max_retries` times with exponential
+/// backoff and jitter when the upstream error looks transient (rate limit or 5xx). The
+/// overall attempt is still bounded by the caller's `timeout`.
+async fn create_with_retry(
+    client: &Client<OpenAIConfig>,
+    request: async_openai::types::CreateChatCompletionRequest,
+    max_retries: u32,
+) -> Result<CreateChatCompletionResponse, OpenAIError> {
+    let mut attempt = 0;
+    loop {
+        match client.chat().create(request.clone()).await {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < max_retries && is_transient(&e) => {
+                let backoff = INITIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt));
+                let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2));
+                tokio::time::sleep(backoff.saturating_add(jitter)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Builds the bounded SSE stream for `/answer/stream`: each upstream delta becomes one
+/// event carrying its incremental content. Every `next()` is individually raced against
+/// `deadline` via `timeout_at`, so a stalled upstream (one that simply stops producing
+/// deltas) is cut off too, not just a stream that keeps delivering past the deadline.
+/// A final `[DONE]` event is always emitted last.
+fn sse_stream(
+    upstream: BoxStream<'static, Result<CreateChatCompletionStreamResponse, OpenAIError>>,
+    deadline: Instant,
+) -> BoxStream<'static, Result<warp::sse::Event, Infallible>> {
+    let deltas = stream::unfold(Some(upstream), move |state| async move {
+        let mut upstream = state?;
+
+        let next = match tokio::time::timeout_at(deadline, upstream.next()).await {
+            Ok(Some(item)) => item,
+            Ok(None) => return None,
+            Err(_) => {
+                let event = warp::sse::Event::default()
+                    .json_data(serde_json::json!({ "error": "stream timed out" }))
+                    .expect("sse event payload is always serializable");
+                return Some((Ok(event), None));
+            }
+        };
+
+        let event = match next {
+            Ok(response) => {
+                let content = response
+                    .choices
+                    .first()
+                    .and_then(|choice| choice.delta.content.clone())
+                    .unwrap_or_default();
+                warp::sse::Event::default().json_data(serde_json::json!({ "content": content }))
+            }
+            Err(e) => warp::sse::Event::default().json_data(serde_json::json!({ "error": e.to_string() })),
+        };
+        let event = event.expect("sse event payload is always serializable");
+
+        Some((Ok(event), Some(upstream)))
+    });
+
+    let done = stream::once(async { Ok(warp::sse::Event::default().data("[DONE]")) });
+
+    deltas.chain(done).boxed()
+}
+
 async fn ping_handler() -> Result<impl Reply, Rejection> {
     let response = PingResponse {
         status: "ok".to_owned(),
@@ -91,38 +263,177 @@ async fn ping_handler() -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&response))
 }
 
-async fn answer_handler(request: OpenAiRequest) -> Result<impl Reply, Rejection> {
-    let response = match get_response(request).await {
+async fn answer_handler(request: OpenAiRequest, profiles: Arc<Profiles>) -> Result<impl Reply, Rejection> {
+    let response = answer_one(request, profiles).await;
+    Ok(warp::reply::json(&response))
+}
+
+/// A single `{"error": ...}` event followed by `[DONE]`, for reporting a failure that
+/// happens before the upstream stream could be established.
+fn error_sse_stream(message: String) -> BoxStream<'static, Result<warp::sse::Event, Infallible>> {
+    let error_event = stream::once(async move {
+        Ok(warp::sse::Event::default()
+            .json_data(serde_json::json!({ "error": message }))
+            .expect("sse event payload is always serializable"))
+    });
+    let done = stream::once(async { Ok(warp::sse::Event::default().data("[DONE]")) });
+    error_event.chain(done).boxed()
+}
+
+async fn answer_stream_handler(request: OpenAiRequest, profiles: Arc<Profiles>) -> Result<impl Reply, Rejection> {
+    let req_timeout = request.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let deadline = Instant::now() + Duration::from_secs(req_timeout);
+
+    if request.messages.is_empty() {
+        let events = error_sse_stream("No response from GPT-3.5 Turbo".to_owned());
+        return Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)));
+    }
+
+    let profile = match resolve_profile(&profiles, &request.profile) {
+        Ok(profile) => profile,
+        Err(e) => {
+            let events = error_sse_stream(e);
+            return Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)));
+        }
+    };
+
+    let config = match build_config(profile) {
+        Ok(config) => config,
+        Err(e) => {
+            let events = error_sse_stream(e);
+            return Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)));
+        }
+    };
+    let client = Client::with_config(config);
+    let converted_messages = converted_messages(&request.messages);
+
+    let chat_request = match build_chat_request(&request, profile, converted_messages) {
+        Ok(req) => req,
+        Err(e) => {
+            let events = error_sse_stream(e.to_string());
+            return Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)));
+        }
+    };
+
+    let upstream = match tokio::time::timeout_at(deadline, client.chat().create_stream(chat_request)).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            let events = error_sse_stream(e.to_string());
+            return Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)));
+        }
+        Err(_) => {
+            let events = error_sse_stream("timed out establishing stream".to_owned());
+            return Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)));
+        }
+    };
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(sse_stream(upstream, deadline))))
+}
+
+/// Resolves a single batch entry into an `ApiResponse`, the same shape `/answer` returns,
+/// so one failing prompt reports its own error without affecting the rest of the batch.
+async fn answer_one(request: OpenAiRequest, profiles: Arc<Profiles>) -> ApiResponse {
+    match get_response(request, &profiles).await {
         Ok(answer) => ApiResponse {
             success: true,
             openai_answer: Some(answer),
-            error: None
+            error: None,
         },
         Err(e) => ApiResponse {
-            success: false, openai_answer: None, error: Some(e.to_string())
+            success: false,
+            openai_answer: None,
+            error: Some(e.to_string()),
         },
-    };
+    }
+}
 
-    Ok(warp::reply::json(&response))
+async fn answer_batch_handler(
+    requests: Vec<OpenAiRequest>,
+    profiles: Arc<Profiles>,
+) -> Result<impl Reply, Rejection> {
+    let mut responses: Vec<(usize, ApiResponse)> = stream::iter(requests.into_iter().enumerate())
+        .map(|(i, request)| {
+            let profiles = profiles.clone();
+            async move { (i, answer_one(request, profiles).await) }
+        })
+        .buffer_unordered(config::max_batch_size())
+        .collect()
+        .await;
+
+    responses.sort_by_key(|(i, _)| *i);
+    let ordered: Vec<ApiResponse> = responses.into_iter().map(|(_, response)| response).collect();
+
+    Ok(warp::reply::json(&ordered))
+}
+
+fn with_profiles(
+    profiles: Arc<Profiles>,
+) -> impl Filter<Extract = (Arc<Profiles>,), Error = Infallible> + Clone {
+    warp::any().map(move || profiles.clone())
 }
 
+/// Resolves once Ctrl+C or SIGTERM is received, so `main` can drain in-flight requests
+/// instead of dropping them when the process is asked to stop.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
 
 #[tokio::main]
 async fn main() {
     std::env::set_var("RUST_LOG", "warn");
 
+    let profiles = Arc::new(config::load_profiles().expect("failed to load profiles"));
+
     let ping_route = warp::path("ping")
         .and(warp::get())
         .and_then(ping_handler);
 
     let answer_route = warp::path("answer")
+        .and(warp::path::end())
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_profiles(profiles.clone()))
         .and_then(answer_handler);
 
-    let routes = answer_route.or(ping_route);
+    let answer_stream_route = warp::path!("answer" / "stream")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_profiles(profiles.clone()))
+        .and_then(answer_stream_handler);
 
-    warp::serve(routes)
-        .run(([0, 0, 0, 0], 8080))
-        .await;
+    let answer_batch_route = warp::path!("answer" / "batch")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_profiles(profiles.clone()))
+        .and_then(answer_batch_handler);
+
+    let routes = answer_route
+        .or(answer_stream_route)
+        .or(answer_batch_route)
+        .or(ping_route);
+
+    let (_addr, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], 8080), shutdown_signal());
+
+    server.await;
 }